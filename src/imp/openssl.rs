@@ -7,29 +7,80 @@ use self::openssl::pkcs12;
 use self::openssl::error::ErrorStack;
 use self::openssl::ssl::{self, SslMethod, SslConnectorBuilder, SslConnector, SslAcceptorBuilder,
                          SslAcceptor, MidHandshakeSslStream, SslOption, SslContextBuilder,
-                         ShutdownResult};
+                         ShutdownResult, SSL_VERIFY_NONE, SSL_VERIFY_PEER,
+                         SSL_VERIFY_FAIL_IF_NO_PEER_CERT};
+use self::openssl::x509::X509;
+use self::openssl::pkey::{PKey, Private};
+use self::openssl::stack::Stack;
+
+// Not exposed as a named constant by the openssl crate at this version.
+const X509_V_ERR_HOSTNAME_MISMATCH: i32 = 62;
 
 use Protocol;
 
-fn supported_protocols(protocols: &[Protocol], ctx: &mut SslContextBuilder) {
+// Ranks protocols from oldest to newest so the fallback implementation below
+// can turn a `[min, max]` range into the right set of `SSL_OP_NO_*` flags.
+fn protocol_rank(protocol: Protocol) -> u8 {
+    match protocol {
+        Protocol::Sslv3 => 0,
+        Protocol::Tlsv10 => 1,
+        Protocol::Tlsv11 => 2,
+        Protocol::Tlsv12 => 3,
+        Protocol::__NonExhaustive => unreachable!(),
+    }
+}
+
+#[cfg(have_min_max_version)]
+fn supported_protocols(min: Option<Protocol>,
+                        max: Option<Protocol>,
+                        ctx: &mut SslContextBuilder)
+                        -> Result<(), Error> {
+    use self::openssl::ssl::SslVersion;
+
+    fn cvt(protocol: Protocol) -> SslVersion {
+        match protocol {
+            Protocol::Sslv3 => SslVersion::SSL3,
+            Protocol::Tlsv10 => SslVersion::TLS1,
+            Protocol::Tlsv11 => SslVersion::TLS1_1,
+            Protocol::Tlsv12 => SslVersion::TLS1_2,
+            Protocol::__NonExhaustive => unreachable!(),
+        }
+    }
+
+    try!(ctx.set_min_proto_version(min.map(cvt)));
+    try!(ctx.set_max_proto_version(max.map(cvt)));
+
+    Ok(())
+}
+
+#[cfg(not(have_min_max_version))]
+fn supported_protocols(min: Option<Protocol>,
+                        max: Option<Protocol>,
+                        ctx: &mut SslContextBuilder)
+                        -> Result<(), Error> {
     // This constant is only defined on OpenSSL 1.0.2 and above, so manually do it.
-    let ssl_op_no_ssl_mask = ssl::SSL_OP_NO_SSLV2 | ssl::SSL_OP_NO_SSLV3 |
-        ssl::SSL_OP_NO_TLSV1 | ssl::SSL_OP_NO_TLSV1_2 | ssl::SSL_OP_NO_TLSV1_2;
+    let no_ssl_mask = ssl::SSL_OP_NO_SSLV2 | ssl::SSL_OP_NO_SSLV3 | ssl::SSL_OP_NO_TLSV1 |
+        ssl::SSL_OP_NO_TLSV1_1 | ssl::SSL_OP_NO_TLSV1_2;
 
     let mut options = ctx.options();
     ctx.clear_options(SslOption::all());
-    options |= ssl_op_no_ssl_mask;
-    for protocol in protocols {
-        let op = match *protocol {
-            Protocol::Sslv3 => ssl::SSL_OP_NO_SSLV3,
-            Protocol::Tlsv10 => ssl::SSL_OP_NO_TLSV1,
-            Protocol::Tlsv11 => ssl::SSL_OP_NO_TLSV1_1,
-            Protocol::Tlsv12 => ssl::SSL_OP_NO_TLSV1_2,
-            Protocol::__NonExhaustive => unreachable!(),
-        };
-        options &= !op;
+    options |= no_ssl_mask;
+
+    let min = min.map(protocol_rank).unwrap_or(protocol_rank(Protocol::Sslv3));
+    let max = max.map(protocol_rank).unwrap_or(protocol_rank(Protocol::Tlsv12));
+
+    for &(protocol, op) in &[(Protocol::Sslv3, ssl::SSL_OP_NO_SSLV3),
+                              (Protocol::Tlsv10, ssl::SSL_OP_NO_TLSV1),
+                              (Protocol::Tlsv11, ssl::SSL_OP_NO_TLSV1_1),
+                              (Protocol::Tlsv12, ssl::SSL_OP_NO_TLSV1_2)] {
+        let rank = protocol_rank(protocol);
+        if rank >= min && rank <= max {
+            options &= !op;
+        }
     }
     ctx.set_options(options);
+
+    Ok(())
 }
 
 pub struct Error(ssl::Error);
@@ -68,13 +119,75 @@ impl From<ErrorStack> for Error {
     }
 }
 
-pub struct Pkcs12(pkcs12::ParsedPkcs12);
+pub struct Pkcs12 {
+    cert: X509,
+    pkey: PKey<Private>,
+    chain: Stack<X509>,
+}
 
 impl Pkcs12 {
     pub fn from_der(buf: &[u8], pass: &str) -> Result<Pkcs12, Error> {
         let pkcs12 = try!(pkcs12::Pkcs12::from_der(buf));
         let parsed = try!(pkcs12.parse(pass));
-        Ok(Pkcs12(parsed))
+        Ok(Pkcs12 {
+            cert: parsed.cert,
+            pkey: parsed.pkey,
+            chain: parsed.chain,
+        })
+    }
+
+    pub fn from_pkcs8(pem_cert_chain: &[u8], pem_key: &[u8]) -> Result<Pkcs12, Error> {
+        let mut certs = try!(X509::stack_from_pem(pem_cert_chain)).into_iter();
+        let cert = match certs.next() {
+            Some(cert) => cert,
+            None => {
+                let err = io::Error::new(io::ErrorKind::InvalidData,
+                                          "no certificates in pem_cert_chain");
+                return Err(Error(ssl::Error::Stream(err)));
+            }
+        };
+        let pkey = try!(PKey::private_key_from_pem(pem_key));
+
+        let mut chain = try!(Stack::new());
+        for cert in certs {
+            try!(chain.push(cert));
+        }
+
+        Ok(Pkcs12 {
+            cert: cert,
+            pkey: pkey,
+            chain: chain,
+        })
+    }
+}
+
+// Encodes a list of protocol names into the wire format expected by
+// `SslContextBuilder::set_alpn_protos`: each entry prefixed by its length.
+fn alpn_list(protocols: &[&str]) -> Result<Vec<u8>, Error> {
+    let mut alpn = Vec::new();
+    for protocol in protocols {
+        if protocol.len() > 255 {
+            let err = io::Error::new(io::ErrorKind::InvalidInput,
+                                      "ALPN protocol identifier longer than 255 bytes");
+            return Err(Error(ssl::Error::Stream(err)));
+        }
+        alpn.push(protocol.len() as u8);
+        alpn.extend_from_slice(protocol.as_bytes());
+    }
+    Ok(alpn)
+}
+
+pub struct Certificate(X509);
+
+impl Certificate {
+    pub fn from_der(buf: &[u8]) -> Result<Certificate, Error> {
+        let cert = try!(X509::from_der(buf));
+        Ok(Certificate(cert))
+    }
+
+    pub fn from_pem(buf: &[u8]) -> Result<Certificate, Error> {
+        let cert = try!(X509::from_pem(buf));
+        Ok(Certificate(cert))
     }
 }
 
@@ -96,6 +209,13 @@ impl<S> MidHandshakeTlsStream<S> {
     pub fn get_mut(&mut self) -> &mut S {
         self.0.get_mut()
     }
+
+    pub fn would_block_direction(&self) -> WouldBlockDirection {
+        match self.0.error().code() {
+            ssl::ErrorCode::WANT_WRITE => WouldBlockDirection::Write,
+            _ => WouldBlockDirection::Read,
+        }
+    }
 }
 
 impl<S> MidHandshakeTlsStream<S>
@@ -109,9 +229,16 @@ impl<S> MidHandshakeTlsStream<S>
     }
 }
 
+/// The direction a handshake is blocked on, so a caller can register the
+/// right interest with its reactor before retrying.
+pub enum WouldBlockDirection {
+    Read,
+    Write,
+}
+
 pub enum HandshakeError<S> {
     Failure(Error),
-    Interrupted(MidHandshakeTlsStream<S>),
+    WouldBlock(MidHandshakeTlsStream<S>),
 }
 
 impl<S> From<ssl::HandshakeError<S>> for HandshakeError<S> {
@@ -122,7 +249,7 @@ impl<S> From<ssl::HandshakeError<S>> for HandshakeError<S> {
             }
             ssl::HandshakeError::Failure(e) => HandshakeError::Failure(Error(e.into_error())),
             ssl::HandshakeError::Interrupted(s) => {
-                HandshakeError::Interrupted(MidHandshakeTlsStream(s))
+                HandshakeError::WouldBlock(MidHandshakeTlsStream(s))
             }
         }
     }
@@ -134,28 +261,77 @@ impl<S> From<ErrorStack> for HandshakeError<S> {
     }
 }
 
-pub struct TlsConnectorBuilder(SslConnectorBuilder);
+pub struct TlsConnectorBuilder {
+    connector: SslConnectorBuilder,
+    min_protocol: Option<Protocol>,
+    max_protocol: Option<Protocol>,
+    accept_invalid_certs: bool,
+    accept_invalid_hostnames: bool,
+}
 
 impl TlsConnectorBuilder {
     pub fn identity(&mut self, pkcs12: Pkcs12) -> Result<(), Error> {
-        let ctx = self.0.builder_mut();
+        let ctx = self.connector.builder_mut();
         // FIXME clear chain certs to clean up if called multiple times
-        try!(ctx.set_certificate(&pkcs12.0.cert));
-        try!(ctx.set_private_key(&pkcs12.0.pkey));
+        try!(ctx.set_certificate(&pkcs12.cert));
+        try!(ctx.set_private_key(&pkcs12.pkey));
         try!(ctx.check_private_key());
-        for cert in pkcs12.0.chain {
+        for cert in pkcs12.chain {
             try!(ctx.add_extra_chain_cert(cert));
         }
         Ok(())
     }
 
-    pub fn supported_protocols(&mut self, protocols: &[Protocol]) -> Result<(), Error> {
-        supported_protocols(protocols, self.0.builder_mut());
+    pub fn min_protocol_version(&mut self, protocol: Option<Protocol>) -> Result<(), Error> {
+        self.min_protocol = protocol;
+        supported_protocols(self.min_protocol, self.max_protocol, self.connector.builder_mut())
+    }
+
+    pub fn max_protocol_version(&mut self, protocol: Option<Protocol>) -> Result<(), Error> {
+        self.max_protocol = protocol;
+        supported_protocols(self.min_protocol, self.max_protocol, self.connector.builder_mut())
+    }
+
+    pub fn add_root_certificate(&mut self, cert: Certificate) -> Result<(), Error> {
+        try!(self.connector.builder_mut().cert_store_mut().add_cert(cert.0));
+        Ok(())
+    }
+
+    pub fn danger_accept_invalid_certs(&mut self, accept_invalid_certs: bool) -> Result<(), Error> {
+        self.accept_invalid_certs = accept_invalid_certs;
+        self.update_verify();
         Ok(())
     }
 
+    pub fn danger_accept_invalid_hostnames(&mut self,
+                                            accept_invalid_hostnames: bool)
+                                            -> Result<(), Error> {
+        self.accept_invalid_hostnames = accept_invalid_hostnames;
+        self.update_verify();
+        Ok(())
+    }
+
+    pub fn request_alpn(&mut self, protocols: &[&str]) -> Result<(), Error> {
+        let protocols = try!(alpn_list(protocols));
+        try!(self.connector.builder_mut().set_alpn_protos(&protocols));
+        Ok(())
+    }
+
+    fn update_verify(&mut self) {
+        let ctx = self.connector.builder_mut();
+        if self.accept_invalid_certs {
+            ctx.set_verify(SSL_VERIFY_NONE);
+        } else if self.accept_invalid_hostnames {
+            ctx.set_verify_callback(SSL_VERIFY_PEER, |preverify_ok, x509_ctx| {
+                preverify_ok || x509_ctx.error().as_raw() == X509_V_ERR_HOSTNAME_MISMATCH
+            });
+        } else {
+            ctx.set_verify(SSL_VERIFY_PEER);
+        }
+    }
+
     pub fn build(self) -> Result<TlsConnector, Error> {
-        Ok(TlsConnector(self.0.build()))
+        Ok(TlsConnector(self.connector.build()))
     }
 }
 
@@ -164,7 +340,13 @@ pub struct TlsConnector(SslConnector);
 impl TlsConnector {
     pub fn builder() -> Result<TlsConnectorBuilder, Error> {
         let builder = try!(SslConnectorBuilder::new(SslMethod::tls()));
-        Ok(TlsConnectorBuilder(builder))
+        Ok(TlsConnectorBuilder {
+            connector: builder,
+            min_protocol: None,
+            max_protocol: None,
+            accept_invalid_certs: false,
+            accept_invalid_hostnames: false,
+        })
     }
 
     pub fn connect<S>(&self, domain: &str, stream: S) -> Result<TlsStream<S>, HandshakeError<S>>
@@ -186,24 +368,66 @@ pub trait TlsConnectorBuilderExt {
 
 impl TlsConnectorBuilderExt for ::TlsConnectorBuilder {
     fn builder(&self) -> &SslConnectorBuilder {
-        &(self.0).0
+        &self.0.connector
     }
 
     fn builder_mut(&mut self) -> &mut SslConnectorBuilder {
-        &mut (self.0).0
+        &mut self.0.connector
     }
 }
 
-pub struct TlsAcceptorBuilder(SslAcceptorBuilder);
+/// Whether a `TlsAcceptor` should request a client certificate during the
+/// handshake, and whether one is required. This is the portable surface
+/// consumers build against; it is mapped to the backend-specific
+/// `SslVerifyMode` only inside this module.
+pub enum ClientCertVerification {
+    /// Do not request a client certificate.
+    NoClientAuth,
+    /// Request a client certificate, but don't require the client to send one.
+    OptionalClientAuth,
+    /// Require the client to send a valid certificate.
+    RequiredClientAuth,
+}
+
+pub struct TlsAcceptorBuilder {
+    acceptor: SslAcceptorBuilder,
+    min_protocol: Option<Protocol>,
+    max_protocol: Option<Protocol>,
+}
 
 impl TlsAcceptorBuilder {
-    pub fn supported_protocols(&mut self, protocols: &[Protocol]) -> Result<(), Error> {
-        supported_protocols(protocols, self.0.builder_mut());
+    pub fn min_protocol_version(&mut self, protocol: Option<Protocol>) -> Result<(), Error> {
+        self.min_protocol = protocol;
+        supported_protocols(self.min_protocol, self.max_protocol, self.acceptor.builder_mut())
+    }
+
+    pub fn max_protocol_version(&mut self, protocol: Option<Protocol>) -> Result<(), Error> {
+        self.max_protocol = protocol;
+        supported_protocols(self.min_protocol, self.max_protocol, self.acceptor.builder_mut())
+    }
+
+    pub fn alpn_protocols(&mut self, protocols: &[&str]) -> Result<(), Error> {
+        let protocols = try!(alpn_list(protocols));
+        self.acceptor.builder_mut().set_alpn_select_callback(move |_ssl, client| {
+            ssl::select_next_proto(&protocols, client).ok_or(ssl::AlpnError::NOACK)
+        });
+        Ok(())
+    }
+
+    pub fn set_verify_client(&mut self, mode: ClientCertVerification) -> Result<(), Error> {
+        let mode = match mode {
+            ClientCertVerification::NoClientAuth => SSL_VERIFY_NONE,
+            ClientCertVerification::OptionalClientAuth => SSL_VERIFY_PEER,
+            ClientCertVerification::RequiredClientAuth => {
+                SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT
+            }
+        };
+        self.acceptor.builder_mut().set_verify(mode);
         Ok(())
     }
 
     pub fn build(self) -> Result<TlsAcceptor, Error> {
-        Ok(TlsAcceptor(self.0.build()))
+        Ok(TlsAcceptor(self.acceptor.build()))
     }
 }
 
@@ -212,8 +436,12 @@ pub struct TlsAcceptor(SslAcceptor);
 impl TlsAcceptor {
     pub fn builder(pkcs12: Pkcs12) -> Result<TlsAcceptorBuilder, Error> {
         let builder = try!(SslAcceptorBuilder::mozilla_intermediate(
-            SslMethod::tls(), &pkcs12.0.pkey, &pkcs12.0.cert, &pkcs12.0.chain));
-        Ok(TlsAcceptorBuilder(builder))
+            SslMethod::tls(), &pkcs12.pkey, &pkcs12.cert, &pkcs12.chain));
+        Ok(TlsAcceptorBuilder {
+            acceptor: builder,
+            min_protocol: None,
+            max_protocol: None,
+        })
     }
 
     pub fn accept<S>(&self, stream: S) -> Result<TlsStream<S>, HandshakeError<S>>
@@ -235,11 +463,11 @@ pub trait TlsAcceptorBuilderExt {
 
 impl TlsAcceptorBuilderExt for ::TlsAcceptorBuilder {
     fn builder(&self) -> &SslAcceptorBuilder {
-        &(self.0).0
+        &self.0.acceptor
     }
 
     fn builder_mut(&mut self) -> &mut SslAcceptorBuilder {
-        &mut (self.0).0
+        &mut self.0.acceptor
     }
 }
 
@@ -279,6 +507,17 @@ impl<S: io::Read + io::Write> TlsStream<S> {
     pub fn get_mut(&mut self) -> &mut S {
         self.0.get_mut()
     }
+
+    pub fn negotiated_alpn(&self) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.0.ssl().selected_alpn_protocol().map(|p| p.to_owned()))
+    }
+
+    pub fn peer_certificate(&self) -> Result<Option<Vec<u8>>, Error> {
+        match self.0.ssl().peer_certificate() {
+            Some(cert) => Ok(Some(try!(cert.to_der()))),
+            None => Ok(None),
+        }
+    }
 }
 
 impl<S: io::Read + io::Write> io::Read for TlsStream<S> {